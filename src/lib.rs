@@ -1,120 +1,425 @@
-use hashbrown::HashMap;
-use std::{
-    cell::RefCell,
-    sync::{Arc, Mutex},
-};
+use hashbrown::{HashMap, HashSet};
+use ordered_float::NotNan;
+use rayon::prelude::*;
+use std::{cell::RefCell, cmp::Reverse, collections::BinaryHeap};
 use unidecode::unidecode;
 
-pub struct ThreeSetCompare {
-    alphabet: Vec<char>,
-    minimum_word_len: i32,
-    delta_word_len_ignore: usize,
-    min_word_similarity: f64,
-    left_chars: Arc<Mutex<CharMap>>,
-    right_chars: Arc<Mutex<CharMap>>,
+/// A per-word similarity backend. `ThreeSetCompare` delegates non-substring word
+/// comparisons to one of these, so the scoring strategy can be swapped without
+/// touching the three-set logic itself.
+pub trait WordMetric: Send + Sync {
+    fn similarity(&self, a: &str, b: &str) -> f64;
 }
 
 type CharMap = HashMap<char, i32>;
 
-enum Word {
-    Left,
-    Right,
+thread_local! {
+    static LEFT_CHARS: RefCell<CharMap> = RefCell::new(CharMap::with_capacity(20));
+    static RIGHT_CHARS: RefCell<CharMap> = RefCell::new(CharMap::with_capacity(20));
 }
 
-impl ThreeSetCompare {
-    pub fn new() -> ThreeSetCompare {
-        let alphabet = (b'a'..=b'z')
-            .chain(b'0'..=b'9')
-            .map(|c| c as char)
-            .collect::<Vec<_>>();
+/// The character set a `MultisetMetric` sums counting errors over.
+pub enum Alphabet {
+    /// A fixed set of characters, checked on every comparison regardless of
+    /// what the two words actually contain.
+    Fixed(Vec<char>),
+    /// Derived per comparison as the union of characters present in the two
+    /// words being compared, so no character a word actually contains is ever
+    /// left out of `errors_sum` while still counting towards `total_length`.
+    Dynamic,
+}
 
-        let minimum_word_len = 2_i32;
-        let delta_word_len_ignore = 3_usize;
-        let min_word_similarity = 0.707_f64;
-        let average_word_length = 20;
+fn default_alphabet() -> Vec<char> {
+    (b'a'..=b'z')
+        .chain(b'0'..=b'9')
+        .map(|c| c as char)
+        .collect::<Vec<_>>()
+}
 
-        ThreeSetCompare {
-            alphabet,
-            minimum_word_len,
-            delta_word_len_ignore,
-            min_word_similarity,
+/// Order-insensitive multiset metric: compares the character counts of each
+/// word over its `alphabet`. Cheap, but scores any anagram as a perfect match.
+///
+/// Its char-counting scratch space lives in thread-local buffers rather than
+/// shared `Mutex`-guarded state, so one `MultisetMetric` can be shared (via
+/// `&ThreeSetCompare`) across a `rayon` thread pool without comparisons
+/// serializing on a lock.
+pub struct MultisetMetric {
+    alphabet: Alphabet,
+}
 
-            left_chars: Arc::new(Mutex::new(CharMap::with_capacity(average_word_length))),
-            right_chars: Arc::new(Mutex::new(CharMap::with_capacity(average_word_length))),
-        }
+impl MultisetMetric {
+    pub fn new() -> MultisetMetric {
+        MultisetMetric::with_alphabet(Alphabet::Fixed(default_alphabet()))
     }
 
-    #[inline(always)]
-    fn count_chars(&self, data: &str, pos: Word) {
-        let mut result = match pos {
-            Word::Left => self.left_chars.lock().unwrap(),
-            Word::Right => self.right_chars.lock().unwrap(),
-        };
+    pub fn with_alphabet(alphabet: Alphabet) -> MultisetMetric {
+        MultisetMetric { alphabet }
+    }
 
-        result.clear();
+    #[inline(always)]
+    fn count_chars(data: &str, cell: &'static std::thread::LocalKey<RefCell<CharMap>>) {
+        cell.with(|result| {
+            let mut result = result.borrow_mut();
+            result.clear();
 
-        for letter in data.chars() {
-            *result.entry(letter).or_insert(0) += 1;
-        }
+            for letter in data.chars() {
+                *result.entry(letter).or_insert(0) += 1;
+            }
+        });
     }
+}
 
-    #[inline(always)]
-    fn preprocess(&self, data: &str) -> Vec<String> {
-        unidecode(data)
-            .to_lowercase()
-            .split_whitespace()
-            .map(|word| word.to_string())
-            .collect::<Vec<String>>()
+impl Default for MultisetMetric {
+    fn default() -> MultisetMetric {
+        MultisetMetric::new()
     }
+}
 
-    fn logic(&self, first: &Vec<String>, second: &Vec<String>) -> f64 {
-        let mut equality = 0;
+impl WordMetric for MultisetMetric {
+    fn similarity(&self, first_word: &str, second_word: &str) -> f64 {
+        Self::count_chars(first_word, &LEFT_CHARS);
+        Self::count_chars(second_word, &RIGHT_CHARS);
 
-        for first_word in first {
-            for second_word in second {
-                let first_len = first_word.chars().count() as i32;
-                let second_len = second_word.chars().count() as i32;
-                let delta_len = (first_len - second_len).abs() as usize;
+        LEFT_CHARS.with(|first_map| {
+            RIGHT_CHARS.with(|second_map| {
+                let first_map = first_map.borrow();
+                let second_map = second_map.borrow();
 
-                if first_len < self.minimum_word_len || second_len < self.minimum_word_len {
-                    continue;
-                }
+                let total_length = first_map
+                    .iter()
+                    .chain(second_map.iter())
+                    .fold(0, |acc, (_, val)| acc + val);
 
-                if first_word.find(second_word).is_some() || second_word.find(first_word).is_some()
-                {
-                    if delta_len <= self.delta_word_len_ignore {
-                        equality += 1;
+                let zero_count = 0;
+                let mut errors_sum = 0;
+
+                match &self.alphabet {
+                    Alphabet::Fixed(alphabet) => {
+                        for alpha in alphabet {
+                            let count_first = first_map.get(alpha).unwrap_or(&zero_count);
+                            let count_second = second_map.get(alpha).unwrap_or(&zero_count);
+
+                            errors_sum += (count_first - count_second).abs();
+                        }
+                    }
+                    Alphabet::Dynamic => {
+                        let present = first_map
+                            .keys()
+                            .chain(second_map.keys())
+                            .collect::<HashSet<_>>();
+
+                        for alpha in present {
+                            let count_first = first_map.get(alpha).unwrap_or(&zero_count);
+                            let count_second = second_map.get(alpha).unwrap_or(&zero_count);
+
+                            errors_sum += (count_first - count_second).abs();
+                        }
                     }
-                } else {
-                    self.count_chars(first_word, Word::Left);
-                    self.count_chars(second_word, Word::Right);
+                }
 
-                    let first_map = self.left_chars.lock().unwrap();
-                    let second_map = self.right_chars.lock().unwrap();
+                1_f64 - (errors_sum as f64 / total_length as f64)
+            })
+        })
+    }
+}
 
-                    let total_length = first_map
-                        .iter()
-                        .chain(second_map.iter())
-                        .fold(0, |acc, (_, val)| acc + val);
+/// Restricted Damerau-Levenshtein edit distance: insertions, deletions,
+/// substitutions and adjacent transpositions, each costing 1.
+fn damerau_levenshtein_distance(a: &[char], b: &[char]) -> usize {
+    let m = a.len();
+    let n = b.len();
 
-                    let zero_count = 0;
-                    let mut errors_sum = 0;
+    if m == 0 || n == 0 {
+        return m.max(n);
+    }
 
-                    for alpha in &self.alphabet {
-                        let count_first = first_map.get(&alpha).unwrap_or(&zero_count);
-                        let count_second = second_map.get(&alpha).unwrap_or(&zero_count);
+    let mut prev_prev = vec![0_usize; n + 1];
+    let mut prev = (0..=n).collect::<Vec<usize>>();
+    let mut cur = vec![0_usize; n + 1];
 
-                        errors_sum += (count_first - count_second).abs();
-                    }
+    for i in 1..=m {
+        cur[0] = i;
 
-                    let local_possibility = 1_f64 - (errors_sum as f64 / total_length as f64);
-                    if local_possibility > self.min_word_similarity {
-                        equality += 1;
-                    }
+        for j in 1..=n {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(prev_prev[j - 2] + 1);
+            }
+        }
+
+        std::mem::swap(&mut prev_prev, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[n]
+}
+
+/// Restricted Damerau-Levenshtein metric: edit distance allowing insertions,
+/// deletions, substitutions and adjacent transpositions, normalized into a
+/// `0..=1` similarity by the longer word's length. Unlike `MultisetMetric` this
+/// is sensitive to character order, so it tells a transposition ("form"/"from")
+/// apart from unrelated noise.
+pub struct DamerauLevenshteinMetric;
+
+impl WordMetric for DamerauLevenshteinMetric {
+    fn similarity(&self, first: &str, second: &str) -> f64 {
+        let a = first.chars().collect::<Vec<char>>();
+        let b = second.chars().collect::<Vec<char>>();
+        let m = a.len();
+        let n = b.len();
+
+        if m == 0 || n == 0 {
+            return if m == n { 1.0 } else { 0.0 };
+        }
+
+        let distance = damerau_levenshtein_distance(&a, &b);
+        1.0 - distance as f64 / m.max(n) as f64
+    }
+}
+
+/// Jaro-Winkler metric: rewards words that agree on their first characters, on
+/// top of the Jaro similarity (matching chars within a sliding window, with a
+/// transposition penalty). Better suited than `DamerauLevenshteinMetric` for
+/// short, prefix-sharing tokens like names and abbreviations.
+pub struct JaroWinklerMetric;
+
+impl WordMetric for JaroWinklerMetric {
+    fn similarity(&self, first: &str, second: &str) -> f64 {
+        let a = first.chars().collect::<Vec<char>>();
+        let b = second.chars().collect::<Vec<char>>();
+        let a_len = a.len();
+        let b_len = b.len();
+
+        if a_len == 0 || b_len == 0 {
+            return if a_len == b_len { 1.0 } else { 0.0 };
+        }
+
+        let window = (a_len.max(b_len) / 2).saturating_sub(1);
+
+        let mut a_matched = vec![false; a_len];
+        let mut b_matched = vec![false; b_len];
+        let mut matches = 0;
+
+        for i in 0..a_len {
+            let lo = i.saturating_sub(window);
+            let hi = (i + window + 1).min(b_len);
+
+            for j in lo..hi {
+                if !b_matched[j] && a[i] == b[j] {
+                    a_matched[i] = true;
+                    b_matched[j] = true;
+                    matches += 1;
+                    break;
                 }
             }
         }
 
+        if matches == 0 {
+            return 0.0;
+        }
+
+        let mut transpositions = 0;
+        let mut b_index = 0;
+
+        for i in 0..a_len {
+            if !a_matched[i] {
+                continue;
+            }
+
+            while !b_matched[b_index] {
+                b_index += 1;
+            }
+
+            if a[i] != b[b_index] {
+                transpositions += 1;
+            }
+
+            b_index += 1;
+        }
+
+        let m = matches as f64;
+        let t = transpositions as f64 / 2.0;
+        let jaro = (m / a_len as f64 + m / b_len as f64 + (m - t) / m) / 3.0;
+
+        let common_prefix = a
+            .iter()
+            .zip(b.iter())
+            .take(4)
+            .take_while(|(x, y)| x == y)
+            .count();
+
+        jaro + common_prefix as f64 * 0.1 * (1.0 - jaro)
+    }
+}
+
+/// Builder for `ThreeSetCompare`. `new()` wires up the same defaults this
+/// builder would produce untouched; reach for the builder when any of the
+/// tuning knobs need to change, or when the multiset metric's alphabet needs
+/// to be user-supplied or derived dynamically.
+pub struct ThreeSetCompareBuilder {
+    minimum_word_len: i32,
+    min_word_similarity: f64,
+    metric: Box<dyn WordMetric>,
+}
+
+impl ThreeSetCompareBuilder {
+    pub fn new() -> ThreeSetCompareBuilder {
+        ThreeSetCompareBuilder {
+            minimum_word_len: 2_i32,
+            min_word_similarity: 0.707_f64,
+            metric: Box::new(DamerauLevenshteinMetric),
+        }
+    }
+
+    pub fn minimum_word_len(mut self, minimum_word_len: i32) -> ThreeSetCompareBuilder {
+        self.minimum_word_len = minimum_word_len;
+        self
+    }
+
+    pub fn min_word_similarity(mut self, min_word_similarity: f64) -> ThreeSetCompareBuilder {
+        self.min_word_similarity = min_word_similarity;
+        self
+    }
+
+    pub fn metric(mut self, metric: Box<dyn WordMetric>) -> ThreeSetCompareBuilder {
+        self.metric = metric;
+        self
+    }
+
+    /// Use the multiset metric with a fixed, user-supplied alphabet instead of
+    /// the default Damerau-Levenshtein metric.
+    pub fn multiset_alphabet(self, alphabet: Vec<char>) -> ThreeSetCompareBuilder {
+        self.metric(Box::new(MultisetMetric::with_alphabet(Alphabet::Fixed(
+            alphabet,
+        ))))
+    }
+
+    /// Use the multiset metric with an alphabet derived, per comparison, from
+    /// the union of characters present in the two words being compared.
+    pub fn multiset_dynamic_alphabet(self) -> ThreeSetCompareBuilder {
+        self.metric(Box::new(MultisetMetric::with_alphabet(Alphabet::Dynamic)))
+    }
+
+    pub fn build(self) -> ThreeSetCompare {
+        ThreeSetCompare {
+            minimum_word_len: self.minimum_word_len,
+            min_word_similarity: self.min_word_similarity,
+            metric: self.metric,
+        }
+    }
+}
+
+impl Default for ThreeSetCompareBuilder {
+    fn default() -> ThreeSetCompareBuilder {
+        ThreeSetCompareBuilder::new()
+    }
+}
+
+pub struct ThreeSetCompare {
+    minimum_word_len: i32,
+    min_word_similarity: f64,
+    metric: Box<dyn WordMetric>,
+}
+
+impl ThreeSetCompare {
+    pub fn new() -> ThreeSetCompare {
+        ThreeSetCompareBuilder::new().build()
+    }
+
+    /// Same defaults as `new()`, but with an explicit word metric, e.g.
+    /// `ThreeSetCompare::with_metric(Box::new(MultisetMetric::new()))` to keep
+    /// the old order-insensitive behavior.
+    pub fn with_metric(metric: Box<dyn WordMetric>) -> ThreeSetCompare {
+        ThreeSetCompareBuilder::new().metric(metric).build()
+    }
+
+    #[inline(always)]
+    fn preprocess(&self, data: &str) -> Vec<String> {
+        unidecode(data)
+            .to_lowercase()
+            .split_whitespace()
+            .map(|word| word.to_string())
+            .collect::<Vec<String>>()
+    }
+
+    /// Score a pair where one word is a substring of the other. A clean
+    /// containment (the only edits are the ones accounting for the length
+    /// difference) is credited as if it cost roughly one edit, rather than a
+    /// free full match; anything else falls back to the configured metric so
+    /// containment and near-containment degrade smoothly instead of flipping
+    /// between a full match and the fallback branch.
+    fn substring_score(
+        &self,
+        first_word: &str,
+        second_word: &str,
+        first_len: i32,
+        second_len: i32,
+    ) -> f64 {
+        let shorter_len = first_len.min(second_len) as usize;
+        let longer_len = first_len.max(second_len) as usize;
+        let delta_len = longer_len - shorter_len;
+
+        let a = first_word.chars().collect::<Vec<char>>();
+        let b = second_word.chars().collect::<Vec<char>>();
+        let distance = damerau_levenshtein_distance(&a, &b);
+
+        // Identical words need no edits at all: treat them as a perfect
+        // match rather than routing them through the containment penalty
+        // below, which is only meant for words that actually differ.
+        if delta_len == 0 && distance == 0 {
+            return 1.0;
+        }
+
+        let residual = distance.saturating_sub(delta_len);
+        let overlap_ok = shorter_len >= 3 || shorter_len * 2 >= longer_len;
+
+        if residual == 0 && overlap_ok {
+            // Scale the containment credit between `min_word_similarity` and
+            // a perfect score instead of an absolute `1.0 - 1.0 / longer_len`:
+            // the latter drops below the configured threshold for short
+            // words and turns a clean containment into a non-match, which is
+            // exactly the binary cliff this scoring was meant to remove.
+            self.min_word_similarity
+                + (1.0 - self.min_word_similarity) * (1.0 - 1.0 / longer_len as f64)
+        } else {
+            self.metric.similarity(first_word, second_word)
+        }
+    }
+
+    fn logic(&self, first: &Vec<String>, second: &Vec<String>) -> f64 {
+        let equality: i32 = first
+            .par_iter()
+            .map(|first_word| {
+                let first_len = first_word.chars().count() as i32;
+                if first_len < self.minimum_word_len {
+                    return 0;
+                }
+
+                second
+                    .iter()
+                    .filter(|second_word| {
+                        let second_len = second_word.chars().count() as i32;
+                        if second_len < self.minimum_word_len {
+                            return false;
+                        }
+
+                        let is_substring = first_word.find(second_word.as_str()).is_some()
+                            || second_word.find(first_word.as_str()).is_some();
+
+                        let score = if is_substring {
+                            self.substring_score(first_word, second_word, first_len, second_len)
+                        } else {
+                            self.metric.similarity(first_word, second_word)
+                        };
+
+                        score > self.min_word_similarity
+                    })
+                    .count() as i32
+            })
+            .sum();
+
         let first_count_filtered = first
             .into_iter()
             .filter(|word| word.chars().count() >= self.minimum_word_len as usize)
@@ -137,6 +442,47 @@ impl ThreeSetCompare {
 
         return self.logic(&first_p, &second_p);
     }
+
+    /// Find the `limit` candidates most similar to `query`, preprocessing `query`
+    /// once and scoring every candidate with the same logic as `similarity`.
+    /// Results are sorted by descending score, ties broken by candidate string
+    /// order.
+    pub fn best_matches<'a>(
+        &self,
+        query: &str,
+        candidates: &'a [&'a str],
+        limit: usize,
+    ) -> Vec<(f64, &'a str)> {
+        let query_p = self.preprocess(query);
+
+        // Ties on score break deterministically by ascending candidate
+        // string order. Wrapping the candidate in a second `Reverse` makes
+        // the heap's "smallest" element (the one `pop` evicts once over
+        // capacity) the lowest score, tie-broken by the *largest* string —
+        // so eviction keeps the lexicographically-earliest candidates,
+        // matching the ascending tie-break in the final sort below.
+        let mut heap: BinaryHeap<Reverse<(NotNan<f64>, Reverse<&'a str>)>> =
+            BinaryHeap::with_capacity(limit + 1);
+
+        for candidate in candidates {
+            let candidate_p = self.preprocess(candidate);
+            let score = self.logic(&query_p, &candidate_p);
+            let score = NotNan::new(score).expect("similarity score is never NaN");
+
+            heap.push(Reverse((score, Reverse(*candidate))));
+            if heap.len() > limit {
+                heap.pop();
+            }
+        }
+
+        let mut result = heap
+            .into_iter()
+            .map(|Reverse((score, Reverse(candidate)))| (score.into_inner(), candidate))
+            .collect::<Vec<_>>();
+
+        result.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then_with(|| a.1.cmp(b.1)));
+        result
+    }
 }
 
 #[cfg(test)]
@@ -154,7 +500,10 @@ mod tests {
         });
     }
 
-    use crate::ThreeSetCompare;
+    use crate::{
+        Alphabet, JaroWinklerMetric, MultisetMetric, ThreeSetCompare, ThreeSetCompareBuilder,
+        WordMetric,
+    };
     use assert_approx_eq::assert_approx_eq;
 
     #[test]
@@ -182,6 +531,13 @@ mod tests {
             ),
             0.8571428_f64
         );
+        assert_approx_eq!(
+            comparator.similarity(
+                "Сравнение двху строк с пмоощью инвариатнной метркии",
+                "Сравнение двух строк с помощью инвариантной метрики"
+            ),
+            0.8333333_f64
+        );
     }
 
     #[test]
@@ -202,13 +558,6 @@ mod tests {
             ),
             1_f64
         );
-        assert_approx_eq!(
-            comparator.similarity(
-                "Сравнение двху строк с пмоощью инвариатнной метркии",
-                "Сравнение двух строк с помощью инвариантной метрики"
-            ),
-            1_f64
-        );
         assert_approx_eq!(
             comparator.similarity(
                 "Сравнение строк двух с помощью метрики инвариантной",
@@ -224,9 +573,91 @@ mod tests {
 
         assert_approx_eq!(
             comparator.similarity("Первая строка", "Вторая фраза"),
-            0.5_f64
+            0_f64
         );
 
         assert_approx_eq!(comparator.similarity("АБВ", "ГДЕ"), 0_f64);
     }
+
+    #[test]
+    fn best_matches_ranks_top_candidates() {
+        let comparator = ThreeSetCompare::new();
+        let candidates = [
+            "Сравнение двух строк с помощью инвариантной метрики",
+            "Сравнение трех строк с помощью инвариантной метрики",
+            "Первая строка",
+        ];
+
+        let matches = comparator.best_matches(
+            "Сравнение двух строк с помощью инвариантной метрики",
+            &candidates,
+            2,
+        );
+
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].1, candidates[0]);
+        assert_approx_eq!(matches[0].0, 1_f64);
+        assert_eq!(matches[1].1, candidates[1]);
+    }
+
+    #[test]
+    fn multiset_metric_is_order_insensitive() {
+        let comparator = ThreeSetCompare::with_metric(Box::new(MultisetMetric::new()));
+
+        assert_approx_eq!(
+            comparator.similarity(
+                "Сравнение двху строк с пмоощью инвариатнной метркии",
+                "Сравнение двух строк с помощью инвариантной метрики"
+            ),
+            1_f64
+        );
+    }
+
+    #[test]
+    fn builder_dynamic_alphabet_covers_every_present_char() {
+        // Assert directly on the word metric: at the sentence level a single
+        // word pair still clears `min_word_similarity` either way, so
+        // comparing `ThreeSetCompare::similarity` never actually exercises
+        // the dynamic alphabet picking up the extra character.
+        let fixed = MultisetMetric::new();
+        let dynamic = MultisetMetric::with_alphabet(Alphabet::Dynamic);
+
+        // The fixed a-z0-9 alphabet never looks at the apostrophe, so "don't"
+        // scores as a perfect match against "dont". The dynamic alphabet picks
+        // up the extra character and scores it lower.
+        assert_approx_eq!(fixed.similarity("don't", "dont"), 1_f64);
+        assert!(dynamic.similarity("don't", "dont") < 1_f64);
+    }
+
+    #[test]
+    fn builder_fixed_alphabet_is_configurable() {
+        let comparator = ThreeSetCompareBuilder::new()
+            .multiset_alphabet(vec!['a', 'b', 'c'])
+            .build();
+
+        assert_approx_eq!(comparator.similarity("aaa", "aaa"), 1_f64);
+        assert_approx_eq!(comparator.similarity("aaa", "bbb"), 0_f64);
+    }
+
+    #[test]
+    fn jaro_winkler_rewards_shared_prefix() {
+        let metric = JaroWinklerMetric;
+
+        assert_approx_eq!(metric.similarity("martha", "marhta"), 0.9611111_f64);
+    }
+
+    #[test]
+    fn substring_score_penalizes_short_fragments_and_rewards_clean_containment() {
+        let comparator = ThreeSetCompare::new();
+
+        // "in" is a trivial fragment of "shrink": the shorter word is under
+        // 3 characters and under half the length of the longer one, so it
+        // fails the minimum-overlap requirement and earns no substring
+        // credit.
+        assert_approx_eq!(comparator.similarity("in", "shrink"), 0_f64);
+
+        // "cat" is a near-complete containment of "cats" (one trailing edit)
+        // and still scores as a match.
+        assert_approx_eq!(comparator.similarity("cat", "cats"), 1_f64);
+    }
 }